@@ -1,4 +1,6 @@
-use midly::{num::u7, Format, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use midly::{
+    num::u7, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+};
 use std::collections::HashSet;
 
 #[derive(Debug)]
@@ -6,7 +8,144 @@ pub enum PerformanceEvent {
     NoteOn(i16),
     NoteOff(i16),
     TimeShift(i16),
+    /// A raw 0..127 MIDI velocity. `event_to_index`/`index_to_event` bucket this down
+    /// to `num_velocity_bins` and back, but the event itself always carries the
+    /// unbucketed value so the codec agrees on units in both directions.
     Velocity(i16),
+    /// `(numerator, denominator_exponent)`, straight from `MetaMessage::TimeSignature`:
+    /// the time signature is `numerator / 2^denominator_exponent`.
+    TimeSignature(u8, u8),
+    /// `(sharps, is_minor)`, straight from `MetaMessage::KeySignature`: negative
+    /// `sharps` means that many flats instead.
+    KeySignature(i8, bool),
+}
+
+/// Parameters of the tokenization vocabulary: how finely time and velocity are
+/// quantized, and how many notes are addressable. The index ranges used by
+/// `event_to_index`/`index_to_event` are all derived from this config, so changing
+/// it changes the vocabulary size accordingly.
+#[derive(Debug, Clone, Copy)]
+pub struct VocabConfig {
+    pub num_notes: i16,
+    pub timeshift_ms: i16,
+    pub num_timeshift_bins: i16,
+    pub num_velocity_bins: i16,
+}
+
+impl Default for VocabConfig {
+    fn default() -> Self {
+        VocabConfig {
+            num_notes: 128,
+            timeshift_ms: 10,
+            num_timeshift_bins: 100,
+            num_velocity_bins: 32,
+        }
+    }
+}
+
+impl VocabConfig {
+    // time signatures are indexed as (numerator - 1) * denominators + denominator_exponent
+    const TIME_SIG_NUMERATORS: i16 = 32;
+    const TIME_SIG_DENOMINATORS: i16 = 6;
+    // key signatures are indexed as (sharps + 7) * 2 + is_minor, sharps ranging -7..=7
+    const KEY_SIG_SHARPS_RANGE: i16 = 15;
+    const KEY_SIG_MODES: i16 = 2;
+
+    /// Checks that the configured bin/range counts tile their fixed MIDI ranges evenly,
+    /// so `event_to_index`/`index_to_event` can't produce a velocity or note index that
+    /// overruns into a neighboring range. Call this before using a config built from
+    /// untrusted input (e.g. CLI flags).
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_velocity_bins <= 0 || 128 % self.num_velocity_bins != 0 {
+            return Err(format!(
+                "num_velocity_bins must be a positive divisor of 128, got {}",
+                self.num_velocity_bins
+            ));
+        }
+        if self.num_notes <= 0 || self.num_notes > 128 {
+            return Err(format!(
+                "num_notes must be between 1 and 128, got {}",
+                self.num_notes
+            ));
+        }
+        if self.timeshift_ms <= 0 {
+            return Err(format!(
+                "timeshift_ms must be positive, got {}",
+                self.timeshift_ms
+            ));
+        }
+        if self.num_timeshift_bins <= 0 {
+            return Err(format!(
+                "num_timeshift_bins must be positive, got {}",
+                self.num_timeshift_bins
+            ));
+        }
+        Ok(())
+    }
+
+    fn velocity_bucket_size(&self) -> i16 {
+        128 / self.num_velocity_bins
+    }
+
+    fn noteoff_offset(&self) -> i16 {
+        self.num_notes
+    }
+
+    fn timeshift_offset(&self) -> i16 {
+        self.num_notes * 2
+    }
+
+    fn velocity_offset(&self) -> i16 {
+        self.timeshift_offset() + self.num_timeshift_bins
+    }
+
+    pub fn vocab_size(&self) -> i16 {
+        self.velocity_offset() + self.num_velocity_bins
+    }
+
+    fn time_signature_offset(&self) -> i16 {
+        self.vocab_size()
+    }
+
+    fn time_signature_count(&self) -> i16 {
+        Self::TIME_SIG_NUMERATORS * Self::TIME_SIG_DENOMINATORS
+    }
+
+    fn key_signature_offset(&self) -> i16 {
+        self.time_signature_offset() + self.time_signature_count()
+    }
+
+    fn key_signature_count(&self) -> i16 {
+        Self::KEY_SIG_SHARPS_RANGE * Self::KEY_SIG_MODES
+    }
+
+    pub fn full_vocab_size(&self) -> i16 {
+        self.key_signature_offset() + self.key_signature_count()
+    }
+}
+
+/// Diagnostics collected while generating a performance's events, surfaced instead of
+/// silently producing a truncated or malformed training sequence.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Notes that were still sounding when the track ended: a `NoteOn` with no matching
+    /// `NoteOff`, or a note whose release was still deferred by the sustain pedal.
+    pub hanging_notes: Vec<i16>,
+    /// Sustain pedal was still held down when the track ended.
+    pub dangling_pedal: bool,
+    /// `NoteOff` events that arrived with no prior unmatched `NoteOn`.
+    pub unmatched_note_offs: Vec<i16>,
+    /// The track contained no tempo message at all.
+    pub missing_tempo: bool,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.hanging_notes.is_empty()
+            && !self.dangling_pedal
+            && self.unmatched_note_offs.is_empty()
+            && !self.missing_tempo
+    }
 }
 
 fn u7_to_i16(v: &u7) -> i16 {
@@ -14,62 +153,214 @@ fn u7_to_i16(v: &u7) -> i16 {
     v as i16
 }
 
-#[allow(dead_code)]
-fn timeshift_to_ms(timeshift: i16) -> i16 {
-    // timeshifts are discretized 10 ms chunks, starting at 0
-    (timeshift + 1) * 10
+fn timeshift_to_ms(timeshift: i16, config: &VocabConfig) -> i16 {
+    (timeshift + 1) * config.timeshift_ms
 }
 
-fn ticks_to_timeshift(ticks: u32, ticks_per_sec: u32) -> u32 {
-    // timeshifts are discretized 10 ms chunks, starting at 0
-    (ticks * 100 - 50) / ticks_per_sec
+fn ticks_to_timeshift(ticks: u32, ticks_per_sec: u32, config: &VocabConfig) -> u32 {
+    let steps_per_sec = (1000 / config.timeshift_ms) as u32;
+    (ticks * steps_per_sec - steps_per_sec / 2) / ticks_per_sec
 }
 
-pub fn event_to_index(event: PerformanceEvent) -> i16 {
+pub fn event_to_index(event: PerformanceEvent, config: &VocabConfig) -> i16 {
+    // MIDI keys run 0..127 regardless of `num_notes`, so a note outside the configured
+    // range is clamped rather than left to overrun into the next range (NoteOff, then
+    // TimeShift, ...).
+    let clamp_note = |v: i16| v.clamp(0, config.num_notes - 1);
     match event {
-        PerformanceEvent::NoteOn(v) => v,
-        PerformanceEvent::NoteOff(v) => v + 128,
-        PerformanceEvent::TimeShift(v) => v + 256,
-        PerformanceEvent::Velocity(v) => v / 4 + 356,
+        PerformanceEvent::NoteOn(v) => clamp_note(v),
+        PerformanceEvent::NoteOff(v) => clamp_note(v) + config.noteoff_offset(),
+        PerformanceEvent::TimeShift(v) => v + config.timeshift_offset(),
+        PerformanceEvent::Velocity(v) => v / config.velocity_bucket_size() + config.velocity_offset(),
+        PerformanceEvent::TimeSignature(numerator, denominator_exp) => {
+            // `MetaMessage::TimeSignature` carries both fields as plain `u8`s, so a
+            // numerator of 0 or > TIME_SIG_NUMERATORS, or a denominator exponent >=
+            // TIME_SIG_DENOMINATORS, are both legal to receive here even though they'd
+            // otherwise over/underflow into a neighboring index range.
+            let numerator = (numerator.max(1) as i16 - 1).min(VocabConfig::TIME_SIG_NUMERATORS - 1);
+            let denominator_exp = (denominator_exp as i16).min(VocabConfig::TIME_SIG_DENOMINATORS - 1);
+            numerator * VocabConfig::TIME_SIG_DENOMINATORS
+                + denominator_exp
+                + config.time_signature_offset()
+        }
+        PerformanceEvent::KeySignature(sharps, is_minor) => {
+            // the spec bounds `sharps` to -7..=7, but clamp defensively since it's a
+            // plain `i8` on the wire and an out-of-range value would otherwise
+            // over/underflow into a neighboring index range.
+            let sharps = (sharps as i16 + 7).clamp(0, VocabConfig::KEY_SIG_SHARPS_RANGE - 1);
+            sharps * VocabConfig::KEY_SIG_MODES + is_minor as i16 + config.key_signature_offset()
+        }
     }
 }
 
-#[allow(dead_code)]
-pub fn index_to_event(idx: i16) -> Result<PerformanceEvent, String> {
-    if idx >= 0 && idx < 128 {
+pub fn index_to_event(idx: i16, config: &VocabConfig) -> Result<PerformanceEvent, String> {
+    if idx >= 0 && idx < config.noteoff_offset() {
         Ok(PerformanceEvent::NoteOn(idx))
-    } else if idx >= 128 && idx < 256 {
-        Ok(PerformanceEvent::NoteOff(idx - 128))
-    } else if idx >= 256 && idx < 356 {
-        Ok(PerformanceEvent::TimeShift(idx - 256))
-    } else if idx >= 356 && idx < 388 {
-        Ok(PerformanceEvent::Velocity(idx - 356))
+    } else if idx < config.timeshift_offset() {
+        Ok(PerformanceEvent::NoteOff(idx - config.noteoff_offset()))
+    } else if idx < config.velocity_offset() {
+        Ok(PerformanceEvent::TimeShift(idx - config.timeshift_offset()))
+    } else if idx < config.vocab_size() {
+        let bucket = idx - config.velocity_offset();
+        Ok(PerformanceEvent::Velocity(bucket * config.velocity_bucket_size()))
+    } else if idx < config.key_signature_offset() {
+        let relative = idx - config.time_signature_offset();
+        let numerator = (relative / VocabConfig::TIME_SIG_DENOMINATORS) + 1;
+        let denominator_exp = relative % VocabConfig::TIME_SIG_DENOMINATORS;
+        Ok(PerformanceEvent::TimeSignature(
+            numerator as u8,
+            denominator_exp as u8,
+        ))
+    } else if idx < config.full_vocab_size() {
+        let relative = idx - config.key_signature_offset();
+        let sharps = (relative / VocabConfig::KEY_SIG_MODES) - 7;
+        let is_minor = relative % VocabConfig::KEY_SIG_MODES == 1;
+        Ok(PerformanceEvent::KeySignature(sharps as i8, is_minor))
     } else {
         Err(String::from(format!("index {} not supported", idx)))
     }
 }
 
-fn merge_parallel_tracks<'a>(tracks: &Vec<Vec<TrackEvent<'a>>>) -> Vec<TrackEvent<'a>> {
-    let mut combined_track = vec![];
-    for track in tracks {
-        let mut t = 0u32;
-        for event in track {
-            let delta: u32 = event.delta.into();
-            t += delta;
-            combined_track.push((event, t));
+/// Reconstructs a playable `Smf` from a sequence of performance events, the inverse of
+/// `midi_to_events`. `ticks_per_beat` and `us_per_beat` fix the tempo of the output file,
+/// since that information isn't carried by the event stream itself.
+pub fn events_to_midi(
+    events: &[PerformanceEvent],
+    ticks_per_beat: u16,
+    us_per_beat: u32,
+    config: &VocabConfig,
+) -> Smf<'static> {
+    let ticks_per_sec = (ticks_per_beat as u32) * 1_000_000 / us_per_beat;
+
+    let mut current_velocity: i16 = 0;
+    let mut pending_ticks: u32 = 0;
+    let mut track: Vec<TrackEvent<'static>> = vec![TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat.into())),
+    }];
+
+    for event in events {
+        match event {
+            PerformanceEvent::TimeShift(v) => {
+                let ms = timeshift_to_ms(*v, config) as u32;
+                pending_ticks += ms * ticks_per_sec / 1000;
+            }
+            PerformanceEvent::Velocity(v) => {
+                // `PerformanceEvent::Velocity` is always a raw 0..127 MIDI velocity, the
+                // same unit `midi_to_events` emits and `event_to_index` buckets down from;
+                // `index_to_event` already expands a decoded bucket back to this unit.
+                current_velocity = *v;
+            }
+            PerformanceEvent::NoteOn(key) => {
+                track.push(TrackEvent {
+                    delta: pending_ticks.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::NoteOn {
+                            key: (*key as u8).into(),
+                            vel: (current_velocity as u8).into(),
+                        },
+                    },
+                });
+                pending_ticks = 0;
+            }
+            PerformanceEvent::NoteOff(key) => {
+                track.push(TrackEvent {
+                    delta: pending_ticks.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::NoteOff {
+                            key: (*key as u8).into(),
+                            vel: 0.into(),
+                        },
+                    },
+                });
+                pending_ticks = 0;
+            }
+            PerformanceEvent::TimeSignature(numerator, denominator) => {
+                track.push(TrackEvent {
+                    delta: pending_ticks.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
+                        *numerator, *denominator, 24, 8,
+                    )),
+                });
+                pending_ticks = 0;
+            }
+            PerformanceEvent::KeySignature(sharps, is_minor) => {
+                track.push(TrackEvent {
+                    delta: pending_ticks.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::KeySignature(*sharps, *is_minor)),
+                });
+                pending_ticks = 0;
+            }
         }
     }
-    combined_track.sort_by_key(|v| v.1);
+
+    track.push(TrackEvent {
+        delta: pending_ticks.into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(ticks_per_beat.into())),
+        tracks: vec![track],
+    }
+}
+
+/// Walks one track's events alongside a running absolute tick position, so its next
+/// event's absolute tick can be compared against other tracks' without flattening
+/// and sorting everything up front.
+struct TrackCursor<'a, 'b> {
+    events: std::iter::Peekable<std::slice::Iter<'b, TrackEvent<'a>>>,
+    abs_tick: u32,
+}
+
+impl<'a, 'b> TrackCursor<'a, 'b> {
+    fn next_abs_tick(&mut self) -> Option<u32> {
+        let delta: u32 = self.events.peek()?.delta.into();
+        Some(self.abs_tick + delta)
+    }
+}
+
+fn merge_parallel_tracks<'a>(tracks: &Vec<Vec<TrackEvent<'a>>>) -> Vec<TrackEvent<'a>> {
+    let mut cursors: Vec<TrackCursor> = tracks
+        .iter()
+        .map(|track| TrackCursor {
+            events: track.iter().peekable(),
+            abs_tick: 0,
+        })
+        .collect();
+
+    let mut combined_track: Vec<TrackEvent<'a>> = vec![];
     let mut prev_t = 0u32;
-    let mut track: Vec<TrackEvent> = vec![];
-    for (event, new_t) in combined_track {
-        track.push(TrackEvent {
+
+    loop {
+        // pick the track whose next event has the smallest absolute tick; ties go to
+        // the lowest track index so ordering stays deterministic
+        let mut next_track: Option<(usize, u32)> = None;
+        for (i, cursor) in cursors.iter_mut().enumerate() {
+            if let Some(candidate_tick) = cursor.next_abs_tick() {
+                if next_track.map_or(true, |(_, best_tick)| candidate_tick < best_tick) {
+                    next_track = Some((i, candidate_tick));
+                }
+            }
+        }
+
+        let (track_idx, new_t) = match next_track {
+            Some(selected) => selected,
+            None => break,
+        };
+        let cursor = &mut cursors[track_idx];
+        let event = cursor.events.next().unwrap();
+        cursor.abs_tick = new_t;
+
+        combined_track.push(TrackEvent {
             delta: (new_t - prev_t).into(),
             kind: event.kind,
         });
         prev_t = new_t;
     }
-    return track;
+    combined_track
 }
 
 #[cfg(test)]
@@ -161,6 +452,178 @@ mod tests {
                 .collect::<Vec<TrackEvent>>()
         );
     }
+
+    #[test]
+    fn simultaneous_events_break_ties_by_track_priority() {
+        // both tracks have an event at absolute tick 0; track 0 must come first
+        let track0 = vec![TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::NoteOn {
+                    key: 60.into(),
+                    vel: 100.into(),
+                },
+            },
+        }];
+        let track1 = vec![TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: 1.into(),
+                message: MidiMessage::NoteOff {
+                    key: 60.into(),
+                    vel: 0.into(),
+                },
+            },
+        }];
+
+        let merged = merge_parallel_tracks(&vec![track0.clone(), track1.clone()]);
+        assert_eq!(merged, vec![track0[0].clone(), track1[0].clone()]);
+
+        // swapping which track is passed first swaps the priority, not the tie-break
+        // outcome itself -- the lower track index always wins
+        let merged_swapped = merge_parallel_tracks(&vec![track1.clone(), track0.clone()]);
+        assert_eq!(merged_swapped, vec![track1[0].clone(), track0[0].clone()]);
+    }
+
+    #[test]
+    fn events_round_trip_through_index_and_midi() {
+        let config = VocabConfig::default();
+        let events = vec![
+            PerformanceEvent::Velocity(100),
+            PerformanceEvent::NoteOn(60),
+            PerformanceEvent::TimeShift(5),
+            PerformanceEvent::NoteOff(60),
+        ];
+        let decoded: Vec<PerformanceEvent> = events
+            .into_iter()
+            .map(|event| index_to_event(event_to_index(event, &config), &config).unwrap())
+            .collect();
+
+        let smf = events_to_midi(&decoded, 480, 500_000, &config);
+        let track = &smf.tracks[0];
+
+        let note_on = track
+            .iter()
+            .find_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, vel },
+                    ..
+                } if u8::from(vel) > 0 => Some((u8::from(key), u8::from(vel))),
+                _ => None,
+            })
+            .expect("expected a NoteOn with nonzero velocity");
+        // 100 is an exact multiple of the default velocity bucket size (4), so the
+        // bucket-and-back round trip is lossless here.
+        assert_eq!(note_on, (60, 100));
+
+        assert!(track.iter().any(|event| matches!(
+            event.kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { key, .. },
+                ..
+            } if u8::from(key) == 60
+        )));
+    }
+
+    #[test]
+    fn split_interval_by_tempo_splits_at_tempo_boundaries() {
+        let ticks_per_beat = 480u16;
+        // tempo doubles (half the us_per_beat) at tick 100
+        let tempo_map = vec![(0u32, 500_000u32), (100u32, 250_000u32)];
+
+        let segments = split_interval_by_tempo(&tempo_map, ticks_per_beat, 50, 100);
+        assert_eq!(segments.len(), 2);
+
+        let first_ticks_per_sec = (ticks_per_beat as u32) * 1_000_000 / 500_000;
+        let second_ticks_per_sec = (ticks_per_beat as u32) * 1_000_000 / 250_000;
+        assert_eq!(segments[0], (50, first_ticks_per_sec));
+        assert_eq!(segments[1], (50, second_ticks_per_sec));
+
+        // an interval entirely before the tempo change isn't split at all
+        let segments = split_interval_by_tempo(&tempo_map, ticks_per_beat, 0, 50);
+        assert_eq!(segments, vec![(50, first_ticks_per_sec)]);
+    }
+
+    #[test]
+    fn validate_rejects_configs_that_would_overrun_their_ranges() {
+        assert!(VocabConfig::default().validate().is_ok());
+
+        let mut non_divisor_bins = VocabConfig::default();
+        non_divisor_bins.num_velocity_bins = 100;
+        assert!(non_divisor_bins.validate().is_err());
+
+        let mut too_many_notes = VocabConfig::default();
+        too_many_notes.num_notes = 200;
+        assert!(too_many_notes.validate().is_err());
+    }
+
+    #[test]
+    fn time_and_key_signature_indices_stay_within_their_own_ranges() {
+        let config = VocabConfig::default();
+        let time_sig_range = config.time_signature_offset()..config.key_signature_offset();
+        let key_sig_range = config.key_signature_offset()..config.full_vocab_size();
+
+        for numerator in [0u8, 1, 32, 200] {
+            for denominator_exp in [0u8, 5, 6, 255] {
+                let idx = event_to_index(
+                    PerformanceEvent::TimeSignature(numerator, denominator_exp),
+                    &config,
+                );
+                assert!(time_sig_range.contains(&idx));
+            }
+        }
+
+        for sharps in [-7i8, 0, 7, i8::MAX, i8::MIN] {
+            for is_minor in [false, true] {
+                let idx = event_to_index(PerformanceEvent::KeySignature(sharps, is_minor), &config);
+                assert!(key_sig_range.contains(&idx));
+            }
+        }
+    }
+
+    #[test]
+    fn real_note_off_releases_the_note_like_a_zero_velocity_note_on() {
+        let config = VocabConfig::default();
+        let mut smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(480.into())),
+            tracks: vec![vec![
+                TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::Tempo(500_000.into())),
+                },
+                TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::NoteOn {
+                            key: 60.into(),
+                            vel: 100.into(),
+                        },
+                    },
+                },
+                TrackEvent {
+                    delta: 10.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::NoteOff {
+                            key: 60.into(),
+                            vel: 0.into(),
+                        },
+                    },
+                },
+            ]],
+        };
+
+        let (events, report) = midi_to_events(&mut smf, &config);
+        assert!(
+            report.hanging_notes.is_empty(),
+            "a real NoteOff should release the note, not leave it hanging"
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, PerformanceEvent::NoteOff(60))));
+    }
 }
 
 fn get_tracks<'a>(smf: &mut Smf<'a>) -> Vec<TrackEvent<'a>> {
@@ -171,52 +634,134 @@ fn get_tracks<'a>(smf: &mut Smf<'a>) -> Vec<TrackEvent<'a>> {
     };
 }
 
-pub fn midi_to_events(smf: &mut Smf) -> Vec<PerformanceEvent> {
+/// Collects every `(absolute_tick, us_per_beat)` tempo change in `tracks`, in order.
+fn build_tempo_map(tracks: &[TrackEvent]) -> Vec<(u32, u32)> {
+    let mut tempo_map = Vec::new();
+    let mut abs_tick = 0u32;
+    for event in tracks {
+        let delta: u32 = event.delta.into();
+        abs_tick += delta;
+        if let TrackEventKind::Meta(MetaMessage::Tempo(x)) = event.kind {
+            tempo_map.push((abs_tick, x.into()));
+        }
+    }
+    tempo_map
+}
+
+fn ticks_per_sec_at(tempo_map: &[(u32, u32)], ticks_per_beat: u16, abs_tick: u32) -> u32 {
+    let us_per_beat = tempo_map
+        .iter()
+        .rev()
+        .find(|&&(t, _)| t <= abs_tick)
+        .map(|&(_, us)| us)
+        .unwrap_or(tempo_map[0].1);
+    (ticks_per_beat as u32) * 1_000_000 / us_per_beat
+}
+
+/// Splits the tick interval `[start_tick, start_tick + length)` at every tempo-change
+/// boundary it crosses, pairing each resulting sub-interval with the `ticks_per_sec`
+/// that applies throughout it.
+fn split_interval_by_tempo(
+    tempo_map: &[(u32, u32)],
+    ticks_per_beat: u16,
+    start_tick: u32,
+    length: u32,
+) -> Vec<(u32, u32)> {
+    let mut segments = Vec::new();
+    let end = start_tick + length;
+    let mut pos = start_tick;
+    while pos < end {
+        let ticks_per_sec = ticks_per_sec_at(tempo_map, ticks_per_beat, pos);
+        let next_boundary = tempo_map
+            .iter()
+            .map(|&(t, _)| t)
+            .find(|&t| t > pos)
+            .unwrap_or(end);
+        let seg_end = next_boundary.min(end);
+        segments.push((seg_end - pos, ticks_per_sec));
+        pos = seg_end;
+    }
+    segments
+}
+
+/// Releases `key`, whether it arrived as a real `MidiMessage::NoteOff` or a `NoteOn`
+/// with velocity 0 -- both mean the same thing. If the sustain pedal is down and the
+/// key is currently held, the release is deferred (moved to `sustained_notes`) until
+/// the pedal comes up; otherwise it's emitted immediately. A release with no matching
+/// held note is recorded as an `unmatched_note_off` rather than emitted.
+fn release_note(
+    key: i16,
+    is_pedal_down: bool,
+    notes_on: &mut HashSet<i16>,
+    sustained_notes: &mut HashSet<i16>,
+    events: &mut Vec<PerformanceEvent>,
+    report: &mut ValidationReport,
+) {
+    if is_pedal_down && notes_on.contains(&key) {
+        sustained_notes.insert(key);
+    } else if notes_on.remove(&key) {
+        events.push(PerformanceEvent::NoteOff(key));
+    } else {
+        report.unmatched_note_offs.push(key);
+    }
+}
+
+pub fn midi_to_events(smf: &mut Smf, config: &VocabConfig) -> (Vec<PerformanceEvent>, ValidationReport) {
     let ticks_per_beat: u16 = match smf.header.timing {
         Timing::Metrical(x) => x.into(),
         _ => panic!("Could not find metric timing header"),
     };
     let tracks = get_tracks(smf);
-    let mut us_per_beat: Option<u32> = None;
-    for event in &tracks {
-        match event.kind {
-            TrackEventKind::Meta(MetaMessage::Tempo(x)) => {
-                us_per_beat = Some(x.into());
-                break;
-            }
-            _ => (),
-        }
+    let tempo_map = build_tempo_map(&tracks);
+    let mut report = ValidationReport::default();
+    if tempo_map.is_empty() {
+        report.missing_tempo = true;
+        return (Vec::new(), report);
     }
-    let us_per_beat = us_per_beat.expect("Could not find tempo message");
-    let ticks_per_sec = (ticks_per_beat as u32) * 1_000_000 / us_per_beat;
 
     let mut is_pedal_down = false;
     let mut events: Vec<PerformanceEvent> = Vec::new();
     let mut sustained_notes: HashSet<i16> = HashSet::new();
     let mut notes_on: HashSet<i16> = HashSet::new();
     let mut previous_ticks = 0;
+    let mut abs_tick = 0u32;
 
     for event in tracks {
-        if event.delta > 0 {
-            let mut ticks: u32 = event.delta.into();
-            // combine repeated delta time events
-            ticks += previous_ticks;
+        let delta: u32 = event.delta.into();
+        abs_tick += delta;
+
+        if delta > 0 {
+            let ticks = delta + previous_ticks;
+            let interval_start = abs_tick - ticks;
 
-            // split up times that are larger than the max time into separate events
+            // split up times that are larger than the max time into separate events,
+            // and further split them at any tempo change the interval crosses
             let mut ticks_chunk = 0;
-            while ticks > 0 {
-                ticks_chunk = if ticks > ticks_per_sec { ticks_per_sec } else { ticks };
-                let timeshift = ticks_to_timeshift(ticks_chunk, ticks_per_sec);
-                let time_event = PerformanceEvent::TimeShift(timeshift as i16);
-                if previous_ticks == 0 {
-                    events.push(time_event);
-                } else {
-                    // update the last time event to combine timeshifts
-                    let last_event_idx = events.len() - 1;
-                    events[last_event_idx] = time_event;
-                    previous_ticks = 0;
+            for (mut seg_ticks, seg_ticks_per_sec) in
+                split_interval_by_tempo(&tempo_map, ticks_per_beat, interval_start, ticks)
+            {
+                let max_chunk_ticks = seg_ticks_per_sec
+                    * (config.num_timeshift_bins as u32)
+                    * (config.timeshift_ms as u32)
+                    / 1000;
+                while seg_ticks > 0 {
+                    ticks_chunk = if seg_ticks > max_chunk_ticks {
+                        max_chunk_ticks
+                    } else {
+                        seg_ticks
+                    };
+                    let timeshift = ticks_to_timeshift(ticks_chunk, seg_ticks_per_sec, config);
+                    let time_event = PerformanceEvent::TimeShift(timeshift as i16);
+                    if previous_ticks == 0 {
+                        events.push(time_event);
+                    } else {
+                        // update the last time event to combine timeshifts
+                        let last_event_idx = events.len() - 1;
+                        events[last_event_idx] = time_event;
+                        previous_ticks = 0;
+                    }
+                    seg_ticks -= ticks_chunk;
                 }
-                ticks -= ticks_chunk;
             }
             // record ticks from the last chunk
             previous_ticks = ticks_chunk;
@@ -228,23 +773,36 @@ pub fn midi_to_events(smf: &mut Smf) -> Vec<PerformanceEvent> {
                 channel: _,
                 message,
             } => match message {
+                MidiMessage::NoteOn { key, vel } if vel == 0 => {
+                    // a NoteOn with velocity 0 is a release, same as a real NoteOff
+                    release_note(
+                        u7_to_i16(&key),
+                        is_pedal_down,
+                        &mut notes_on,
+                        &mut sustained_notes,
+                        &mut events,
+                        &mut report,
+                    );
+                }
+                MidiMessage::NoteOff { key, vel: _ } => {
+                    release_note(
+                        u7_to_i16(&key),
+                        is_pedal_down,
+                        &mut notes_on,
+                        &mut sustained_notes,
+                        &mut events,
+                        &mut report,
+                    );
+                }
                 MidiMessage::NoteOn { key, vel } => {
                     let key = u7_to_i16(&key);
-                    if vel == 0 {
-                        if is_pedal_down && notes_on.contains(&key) {
-                            sustained_notes.insert(key);
-                        } else if notes_on.remove(&key) {
-                            events.push(PerformanceEvent::NoteOff(key));
-                        }
-                    } else {
-                        if sustained_notes.remove(&key) {
-                            events.push(PerformanceEvent::NoteOff(key));
-                            events.push(PerformanceEvent::Velocity(u7_to_i16(&vel)));
-                            events.push(PerformanceEvent::NoteOn(key));
-                        } else if notes_on.insert(key) {
-                            events.push(PerformanceEvent::Velocity(u7_to_i16(&vel)));
-                            events.push(PerformanceEvent::NoteOn(key));
-                        }
+                    if sustained_notes.remove(&key) {
+                        events.push(PerformanceEvent::NoteOff(key));
+                        events.push(PerformanceEvent::Velocity(u7_to_i16(&vel)));
+                        events.push(PerformanceEvent::NoteOn(key));
+                    } else if notes_on.insert(key) {
+                        events.push(PerformanceEvent::Velocity(u7_to_i16(&vel)));
+                        events.push(PerformanceEvent::NoteOn(key));
                     }
                 }
                 MidiMessage::Controller { controller, value } if controller == 64 => {
@@ -260,6 +818,12 @@ pub fn midi_to_events(smf: &mut Smf) -> Vec<PerformanceEvent> {
                 }
                 _ => {}
             },
+            TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                events.push(PerformanceEvent::TimeSignature(numerator, denominator));
+            }
+            TrackEventKind::Meta(MetaMessage::KeySignature(sharps, is_minor)) => {
+                events.push(PerformanceEvent::KeySignature(sharps, is_minor));
+            }
             _ => {}
         }
         if events.len() > events_len_start {
@@ -267,5 +831,10 @@ pub fn midi_to_events(smf: &mut Smf) -> Vec<PerformanceEvent> {
         }
     }
 
-    events
+    // sustained_notes is always a subset of notes_on (pedal-held notes stay in notes_on
+    // until their NoteOff is actually emitted), so notes_on alone covers both
+    report.hanging_notes = notes_on.into_iter().collect();
+    report.dangling_pedal = is_pedal_down;
+
+    (events, report)
 }
@@ -1,10 +1,23 @@
 use midly::Smf;
-use std::{env, fs, io::Error, path::Path};
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    env, fs,
+    io::Error,
+    path::{Path, PathBuf},
+};
 use tch::Tensor;
 
 mod lib;
 
-fn convert_directory_recursively(input_path: &str, output_path: &str) -> Result<(), Error> {
+/// Recursively mirrors `input_path` under `output_path`, creating output subdirectories
+/// as it goes, and collects every file found along with the `.pt` path it should be
+/// converted to. Files are not filtered by type; a non-MIDI file fails later at parsing.
+fn gather_midi_files(
+    input_path: &str,
+    output_path: &str,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<(), Error> {
     if !Path::new(output_path).is_dir() {
         fs::create_dir_all(&output_path).expect(&format!(
             "could not create output directory '{}'",
@@ -17,40 +30,185 @@ fn convert_directory_recursively(input_path: &str, output_path: &str) -> Result<
         let path = entry?.path();
         let name = path.file_name().unwrap().to_str().unwrap();
         if path.metadata()?.is_dir() {
-            println!("processing {}...", name);
             let output_subdir = format!("{}/{}", output_path, name);
-            convert_directory_recursively(path.to_str().unwrap(), &output_subdir)?;
+            gather_midi_files(path.to_str().unwrap(), &output_subdir, files)?;
             continue;
         }
-        let data = fs::read(&path).expect(&format!("Could not read file {:?}", path));
-        let mut smf = match Smf::parse(&data) {
-            Ok(smf) => smf,
-            Err(error) => {
-                println!(
-                    "Failed to parse file {:?} due to midly error: {}",
-                    path, error
-                );
-                continue;
-            }
-        };
-        let events: Vec<i16> = lib::midi_to_events(&mut smf)
-            .into_iter()
-            .map(|x| lib::event_to_index(x))
-            .collect();
         let output_name = format!("{}/{}.pt", output_path, name);
-        println!("{}", output_name);
-        Tensor::of_slice(&events)
-            .save(output_name)
-            .expect("unable to save events to pytorch file");
+        files.push((path, output_name));
+    }
+    Ok(())
+}
+
+/// Converts one file, returning the validation diagnostics collected along the way.
+/// In `strict` mode, a file with any diagnostics has its `.pt` output skipped rather
+/// than silently emitting a truncated sequence.
+fn convert_file(
+    path: &Path,
+    output_name: &str,
+    config: &lib::VocabConfig,
+    strict: bool,
+) -> Result<lib::ValidationReport, String> {
+    let data = fs::read(path).map_err(|error| format!("could not read file {:?}: {}", path, error))?;
+    let mut smf = Smf::parse(&data)
+        .map_err(|error| format!("failed to parse file {:?} due to midly error: {}", path, error))?;
+    let (raw_events, report) = lib::midi_to_events(&mut smf, config);
+    if strict && !report.is_clean() {
+        return Ok(report);
+    }
+    let events: Vec<i16> = raw_events
+        .into_iter()
+        .map(|x| lib::event_to_index(x, config))
+        .collect();
+    Tensor::of_slice(&events)
+        .save(output_name)
+        .map_err(|error| format!("unable to save events for {:?}: {}", path, error))?;
+    Ok(report)
+}
+
+fn convert_directory_recursively(
+    input_path: &str,
+    output_path: &str,
+    config: &lib::VocabConfig,
+    jobs: Option<usize>,
+    strict: bool,
+) -> Result<(), Error> {
+    let mut files = vec![];
+    gather_midi_files(input_path, output_path, &mut files)?;
+
+    let total = files.len();
+    let processed = AtomicUsize::new(0);
+    let convert_all = || {
+        files.par_iter().for_each(|(path, output_name)| {
+            let result = convert_file(path, output_name, config, strict);
+            let done = processed.fetch_add(1, Ordering::SeqCst) + 1;
+            match result {
+                Ok(report) if report.is_clean() => {
+                    println!("[{}/{}] {}", done, total, output_name)
+                }
+                Ok(report) if strict => {
+                    println!(
+                        "[{}/{}] skipped {} (strict mode, diagnostics: {:?})",
+                        done, total, output_name, report
+                    )
+                }
+                Ok(report) => println!(
+                    "[{}/{}] {} (warning, diagnostics: {:?})",
+                    done, total, output_name, report
+                ),
+                Err(error) => println!("[{}/{}] {}", done, total, error),
+            }
+        });
+    };
+
+    match jobs {
+        Some(num_threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .expect("failed to build thread pool")
+            .install(convert_all),
+        None => convert_all(),
     }
+
     Ok(())
 }
 
+fn decode_tensor_to_midi(
+    input_path: &str,
+    output_path: &str,
+    config: &lib::VocabConfig,
+) -> Result<(), Error> {
+    let tensor = Tensor::load(input_path)
+        .expect(&format!("could not load tensor file {}", input_path));
+    let num_tokens = tensor.size()[0];
+    let events: Vec<lib::PerformanceEvent> = (0..num_tokens)
+        .map(|i| tensor.int64_value(&[i]) as i16)
+        .map(|idx| lib::index_to_event(idx, config).expect("encountered an unsupported token index"))
+        .collect();
+    let smf = lib::events_to_midi(&events, 480, 500_000, config);
+    let mut buffer = Vec::new();
+    smf.write(&mut buffer)
+        .expect("unable to serialize midi file");
+    fs::write(output_path, buffer)?;
+    Ok(())
+}
+
+const FLAGS_WITH_VALUES: [&str; 5] = [
+    "--timeshift-ms",
+    "--timeshift-bins",
+    "--velocity-bins",
+    "--num-notes",
+    "--jobs",
+];
+
+fn parse_vocab_config(args: &[String]) -> lib::VocabConfig {
+    let mut config = lib::VocabConfig::default();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--timeshift-ms" => {
+                config.timeshift_ms = args[i + 1]
+                    .parse()
+                    .expect("--timeshift-ms must be an integer");
+            }
+            "--timeshift-bins" => {
+                config.num_timeshift_bins = args[i + 1]
+                    .parse()
+                    .expect("--timeshift-bins must be an integer");
+            }
+            "--velocity-bins" => {
+                config.num_velocity_bins = args[i + 1]
+                    .parse()
+                    .expect("--velocity-bins must be an integer");
+            }
+            "--num-notes" => {
+                config.num_notes = args[i + 1].parse().expect("--num-notes must be an integer");
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    config.validate().expect("invalid vocab config");
+    config
+}
+
+fn parse_jobs(args: &[String]) -> Option<usize> {
+    let i = args.iter().position(|arg| arg == "--jobs")?;
+    Some(
+        args[i + 1]
+            .parse()
+            .expect("--jobs must be a positive integer"),
+    )
+}
+
+const BOOL_FLAGS: [&str; 1] = ["--strict"];
+
+fn positional_args(args: &[String]) -> Vec<String> {
+    let mut positional = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        if FLAGS_WITH_VALUES.contains(&args[i].as_str()) {
+            i += 2;
+        } else if BOOL_FLAGS.contains(&args[i].as_str()) {
+            i += 1;
+        } else {
+            positional.push(args[i].clone());
+            i += 1;
+        }
+    }
+    positional
+}
+
 fn main() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    let input_path = &args[1];
-    let output_path = &args[2];
-    convert_directory_recursively(input_path, output_path)?;
+    let config = parse_vocab_config(&args);
+    let jobs = parse_jobs(&args);
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let args = positional_args(&args);
+    match args[1].as_str() {
+        "decode" => decode_tensor_to_midi(&args[2], &args[3], &config)?,
+        _ => convert_directory_recursively(&args[1], &args[2], &config, jobs, strict)?,
+    }
     println!("done!");
     Ok(())
 }